@@ -37,31 +37,45 @@
 //! # use rust_bert::pipelines::ner::Entity;
 //! # let output =
 //! [
-//!     Entity {
-//!         word: String::from("Amy"),
-//!         score: 0.9986,
-//!         label: String::from("I-PER"),
-//!     },
-//!     Entity {
-//!         word: String::from("Paris"),
-//!         score: 0.9985,
-//!         label: String::from("I-LOC"),
-//!     },
-//!     Entity {
-//!         word: String::from("Paris"),
-//!         score: 0.9988,
-//!         label: String::from("I-LOC"),
-//!     },
-//!     Entity {
-//!         word: String::from("France"),
-//!         score: 0.9993,
-//!         label: String::from("I-LOC"),
-//!     },
+//!     [
+//!         Entity {
+//!             word: String::from("Amy"),
+//!             score: 0.9986,
+//!             label: String::from("PER"),
+//!             start: 11,
+//!             end: 14,
+//!         },
+//!         Entity {
+//!             word: String::from("Paris"),
+//!             score: 0.9985,
+//!             label: String::from("LOC"),
+//!             start: 26,
+//!             end: 31,
+//!         },
+//!     ],
+//!     [
+//!         Entity {
+//!             word: String::from("Paris"),
+//!             score: 0.9988,
+//!             label: String::from("LOC"),
+//!             start: 0,
+//!             end: 5,
+//!         },
+//!         Entity {
+//!             word: String::from("France"),
+//!             score: 0.9993,
+//!             label: String::from("LOC"),
+//!             start: 19,
+//!             end: 25,
+//!         },
+//!     ],
 //! ]
 //! # ;
 //! ```
 
-use crate::pipelines::token_classification::{TokenClassificationConfig, TokenClassificationModel};
+use crate::pipelines::token_classification::{
+    AggregationStrategy, Token, TokenClassificationConfig, TokenClassificationModel,
+};
 
 #[derive(Debug)]
 /// # Entity generated by a `NERModel`
@@ -72,6 +86,68 @@ pub struct Entity {
     pub score: f64,
     /// Entity label (e.g. ORG, LOC...)
     pub label: String,
+    /// Character start offset of the entity in the original input sentence
+    pub start: usize,
+    /// Character end offset of the entity in the original input sentence
+    pub end: usize,
+}
+
+/// Accumulates the tokens making up an `Entity` while it is being merged
+struct EntityBuilder {
+    sentence: usize,
+    word: String,
+    label: String,
+    scores: Vec<f64>,
+    start: usize,
+    end: usize,
+}
+
+impl EntityBuilder {
+    fn new(token: &Token, entity_type: &str) -> EntityBuilder {
+        let (start, end) = token
+            .offset
+            .map(|offset| (offset.begin, offset.end))
+            .unwrap_or((0, 0));
+        EntityBuilder {
+            sentence: token.sentence,
+            word: NERModel::clean_token_text(&token.text),
+            label: entity_type.to_string(),
+            scores: vec![token.score],
+            start,
+            end,
+        }
+    }
+
+    fn extend(&mut self, token: &Token) {
+        // A gap between the previous token's end offset and this token's start
+        // offset means the original text had a separator (typically a space)
+        // between them; a contiguous offset means this token is a wordpiece/
+        // subword continuation of the previous one and should be glued on
+        // directly, regardless of the tokenizer's marker convention (`##`,
+        // `▁`...).
+        let contiguous = token
+            .offset
+            .map_or(true, |offset| offset.begin <= self.end);
+        if !contiguous {
+            self.word.push(' ');
+        }
+        self.word.push_str(&NERModel::clean_token_text(&token.text));
+        self.scores.push(token.score);
+        if let Some(offset) = token.offset {
+            self.end = offset.end;
+        }
+    }
+
+    fn build(self, aggregation_strategy: AggregationStrategy) -> Entity {
+        let score = aggregation_strategy.aggregate(&self.scores);
+        Entity {
+            word: self.word,
+            score,
+            label: self.label,
+            start: self.start,
+            end: self.end,
+        }
+    }
 }
 
 //type alias for some backward compatibility
@@ -80,6 +156,7 @@ type NERConfig = TokenClassificationConfig;
 /// # NERModel to extract named entities
 pub struct NERModel {
     token_classification_model: TokenClassificationModel,
+    aggregation_strategy: AggregationStrategy,
 }
 
 impl NERModel {
@@ -87,7 +164,7 @@ impl NERModel {
     ///
     /// # Arguments
     ///
-    /// * `ner_config` - `NERConfig` object containing the resource references (model, vocabulary, configuration) and device placement (CPU/GPU)
+    /// * `ner_config` - `NERConfig` object containing the resource references (model, vocabulary, configuration), device placement (CPU/GPU) and the `AggregationStrategy` used to score merged entities
     ///
     /// # Example
     ///
@@ -100,9 +177,11 @@ impl NERModel {
     /// # }
     /// ```
     pub fn new(ner_config: NERConfig) -> failure::Fallible<NERModel> {
+        let aggregation_strategy = ner_config.aggregation_strategy;
         let model = TokenClassificationModel::new(ner_config)?;
         Ok(NERModel {
             token_classification_model: model,
+            aggregation_strategy,
         })
     }
 
@@ -114,7 +193,7 @@ impl NERModel {
     ///
     /// # Returns
     ///
-    /// * `Vec<Entity>` containing extracted entities
+    /// * `Vec<Vec<Entity>>` containing extracted entities for each input sentence
     ///
     /// # Example
     ///
@@ -131,16 +210,227 @@ impl NERModel {
     /// # Ok(())
     /// # }
     /// ```
-    pub fn predict(&self, input: &[&str]) -> Vec<Entity> {
-        self.token_classification_model
-            .predict(input, true, false)
-            .into_iter()
-            .filter(|token| token.label != "O")
-            .map(|token| Entity {
-                word: token.text,
-                score: token.score,
-                label: token.label,
-            })
-            .collect()
+    pub fn predict(&self, input: &[&str]) -> Vec<Vec<Entity>> {
+        let tokens = self.token_classification_model.predict(input, true, false);
+        Self::consolidate_entities(tokens.into_iter(), input.len(), self.aggregation_strategy)
+    }
+
+    /// Tag every token of a text, including non-entity (`O`) tokens
+    ///
+    /// Unlike [`NERModel::predict`], this does not merge consecutive tokens of
+    /// the same entity into a single span: each returned `Entity` corresponds
+    /// to a single word (wordpiece continuations are merged into their leading
+    /// piece, whose tag and score are kept), carries its raw tag (e.g. `B-PER`,
+    /// `I-PER`, `O`) as `label`, and is not aggregated with its neighbours. Useful for
+    /// downstream uses that need the full tagged sequence (sequence
+    /// relabeling, visualization, entity density computation...).
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - `&[&str]` Array of texts to tag.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Vec<Entity>>` one `Entity` per input word, grouped per input sentence
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> failure::Fallible<()> {
+    /// # use rust_bert::pipelines::ner::NERModel;
+    ///
+    /// let ner_model = NERModel::new(Default::default())?;
+    /// let input = ["My name is Amy."];
+    /// let output = ner_model.predict_full(&input);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn predict_full(&self, input: &[&str]) -> Vec<Vec<Entity>> {
+        let tokens = self.token_classification_model.predict(input, true, false);
+        Self::group_tokens_into_sentences(tokens.into_iter(), input.len())
+    }
+
+    /// Converts every raw `Token` into an `Entity` (no BIO merging), grouping
+    /// them per source sentence
+    fn group_tokens_into_sentences(
+        tokens: impl Iterator<Item = Token>,
+        num_sentences: usize,
+    ) -> Vec<Vec<Entity>> {
+        let mut sentences: Vec<Vec<Entity>> = (0..num_sentences).map(|_| Vec::new()).collect();
+        for token in tokens {
+            sentences[token.sentence].push(Self::token_to_entity(&token));
+        }
+        sentences
+    }
+
+    /// Converts a single raw `Token` into an `Entity` without any BIO merging
+    fn token_to_entity(token: &Token) -> Entity {
+        let (start, end) = token
+            .offset
+            .map(|offset| (offset.begin, offset.end))
+            .unwrap_or((0, 0));
+        Entity {
+            word: Self::clean_token_text(&token.text),
+            score: token.score,
+            label: token.label.clone(),
+            start,
+            end,
+        }
+    }
+
+    /// Groups consecutive tokens sharing a BIO tag into a single `Entity`,
+    /// keeping entities grouped per source sentence
+    ///
+    /// A new entity is started on a `B-XXX` tag, on an `I-XXX` tag whose type
+    /// differs from the entity currently being built, or when moving to a new
+    /// sentence. Consecutive `I-XXX` tokens of the same type (within the same
+    /// sentence) extend the current entity, which is closed as soon as a
+    /// token of a different type or an `O` tag is encountered. `O` tokens are
+    /// not returned.
+    fn consolidate_entities(
+        tokens: impl Iterator<Item = Token>,
+        num_sentences: usize,
+        aggregation_strategy: AggregationStrategy,
+    ) -> Vec<Vec<Entity>> {
+        let mut sentences: Vec<Vec<Entity>> = (0..num_sentences).map(|_| Vec::new()).collect();
+        let mut current: Option<EntityBuilder> = None;
+
+        for token in tokens {
+            if token.label == "O" {
+                if let Some(builder) = current.take() {
+                    sentences[builder.sentence].push(builder.build(aggregation_strategy));
+                }
+                continue;
+            }
+            let (tag, entity_type) = Self::split_tag(&token.label);
+            let starts_new_entity = match &current {
+                Some(builder) if builder.sentence == token.sentence && tag == "I" => {
+                    builder.label != entity_type
+                }
+                _ => true,
+            };
+
+            if starts_new_entity {
+                if let Some(builder) = current.take() {
+                    sentences[builder.sentence].push(builder.build(aggregation_strategy));
+                }
+                current = Some(EntityBuilder::new(&token, entity_type));
+            } else if let Some(builder) = current.as_mut() {
+                builder.extend(&token);
+            }
+        }
+        if let Some(builder) = current.take() {
+            sentences[builder.sentence].push(builder.build(aggregation_strategy));
+        }
+        sentences
+    }
+
+    /// Splits a BIO tag (e.g. `B-PER`) into its prefix (`B`) and entity type (`PER`)
+    fn split_tag(label: &str) -> (&str, &str) {
+        match label.find('-') {
+            Some(index) => (&label[..index], &label[index + 1..]),
+            None => (label, ""),
+        }
+    }
+
+    /// Strips wordpiece/subword continuation markers from a token surface string
+    fn clean_token_text(text: &str) -> String {
+        text.trim_start_matches("##")
+            .trim_start_matches('▁')
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_tokenizers::{Mask, Offset};
+
+    fn token(text: &str, label: &str, sentence: usize, begin: usize, end: usize) -> Token {
+        Token {
+            text: text.to_string(),
+            score: 0.9,
+            label: label.to_string(),
+            sentence,
+            offset: Some(Offset { begin, end }),
+            mask: Mask::None,
+        }
+    }
+
+    #[test]
+    fn merges_multi_word_entity_with_a_space_on_offset_gap() {
+        let tokens = vec![
+            token("Barux", "B-PER", 0, 11, 16),
+            token("Spinoza", "I-PER", 0, 17, 24),
+        ];
+        let entities =
+            NERModel::consolidate_entities(tokens.into_iter(), 1, AggregationStrategy::Average);
+        assert_eq!(entities[0].len(), 1);
+        assert_eq!(entities[0][0].word, "Barux Spinoza");
+        assert_eq!(entities[0][0].start, 11);
+        assert_eq!(entities[0][0].end, 24);
+    }
+
+    #[test]
+    fn merges_contiguous_wordpiece_offsets_without_a_space() {
+        let tokens = vec![token("Bar", "B-PER", 0, 0, 3), token("ух", "I-PER", 0, 3, 5)];
+        let entities =
+            NERModel::consolidate_entities(tokens.into_iter(), 1, AggregationStrategy::Average);
+        assert_eq!(entities[0].len(), 1);
+        assert_eq!(entities[0][0].word, "Barух");
+    }
+
+    #[test]
+    fn adjacent_same_type_b_tags_start_separate_entities() {
+        let tokens = vec![
+            token("Paris", "B-LOC", 0, 0, 5),
+            token("London", "B-LOC", 0, 6, 12),
+        ];
+        let entities =
+            NERModel::consolidate_entities(tokens.into_iter(), 1, AggregationStrategy::Average);
+        assert_eq!(entities[0].len(), 2);
+        assert_eq!(entities[0][0].word, "Paris");
+        assert_eq!(entities[0][1].word, "London");
+    }
+
+    #[test]
+    fn entities_do_not_merge_across_sentence_boundaries() {
+        let tokens = vec![
+            token("Paris", "I-LOC", 0, 0, 5),
+            token("Paris", "I-LOC", 1, 0, 5),
+        ];
+        let entities =
+            NERModel::consolidate_entities(tokens.into_iter(), 2, AggregationStrategy::Average);
+        assert_eq!(entities[0].len(), 1);
+        assert_eq!(entities[1].len(), 1);
+        assert_eq!(entities[0][0].word, "Paris");
+        assert_eq!(entities[1][0].word, "Paris");
+    }
+
+    #[test]
+    fn predict_full_retains_o_tokens_and_raw_per_token_labels() {
+        let tokens = vec![
+            token("My", "O", 0, 0, 2),
+            token("▁Amy", "B-PER", 0, 3, 6),
+        ];
+        let sentences = NERModel::group_tokens_into_sentences(tokens.into_iter(), 1);
+        assert_eq!(sentences[0].len(), 2);
+        assert_eq!(sentences[0][0].word, "My");
+        assert_eq!(sentences[0][0].label, "O");
+        assert_eq!(sentences[0][1].word, "Amy");
+        assert_eq!(sentences[0][1].label, "B-PER");
+    }
+
+    #[test]
+    fn predict_full_groups_tokens_per_source_sentence() {
+        let tokens = vec![
+            token("Paris", "B-LOC", 0, 0, 5),
+            token("France", "B-LOC", 1, 0, 6),
+        ];
+        let sentences = NERModel::group_tokens_into_sentences(tokens.into_iter(), 2);
+        assert_eq!(sentences[0].len(), 1);
+        assert_eq!(sentences[1].len(), 1);
+        assert_eq!(sentences[0][0].word, "Paris");
+        assert_eq!(sentences[1][0].word, "France");
     }
 }
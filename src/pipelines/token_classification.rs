@@ -0,0 +1,314 @@
+// Copyright 2019-present, the HuggingFace Inc. team, The Google AI Language Team and Facebook, Inc.
+// Copyright 2019 Guillaume Becquin
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//     http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Token classification pipeline (Named Entity Recognition, Part-of-Speech tagging)
+//! Generic token classification pipeline, used by [`super::ner::NERModel`] and suitable
+//! for other token-level tasks (e.g. Part-of-Speech tagging) sharing the same
+//! tag-per-token model shape.
+
+use crate::bert::{
+    BertConfigResources, BertForTokenClassification, BertModelResources, BertVocabResources,
+};
+use crate::pipelines::common::{ModelType, TokenizerOption};
+use crate::resources::{RemoteResource, Resource};
+use rust_tokenizers::{Mask, Offset};
+use tch::{nn, no_grad, Device, Tensor};
+
+/// # A tagged token produced by a `TokenClassificationModel`
+#[derive(Debug, Clone)]
+pub struct Token {
+    /// String representation of the token
+    pub text: String,
+    /// Confidence score for the predicted label
+    pub score: f64,
+    /// Predicted label (e.g. `B-PER`, `I-LOC`, `O`...)
+    pub label: String,
+    /// Index of the sentence (within the `&[&str]` input slice) this token originates from
+    pub sentence: usize,
+    /// Character offset of this token into its source sentence, when available
+    pub offset: Option<Offset>,
+    /// Whether this token is a standalone word or a continuation of the previous one
+    /// (e.g. a wordpiece continuation, tagged `Mask::Continuation` by the tokenizer)
+    pub mask: Mask,
+}
+
+/// # Strategy used to compute the confidence score of a merged, multi-token entity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregationStrategy {
+    /// Softmax probability of the leading token's predicted class. `Token::score` is
+    /// already that softmax probability, so this is currently equivalent to `First`;
+    /// it is kept as its own variant for parity with the aggregation strategies
+    /// exposed by other NER pipelines.
+    Simple,
+    /// Score of the entity's first token
+    First,
+    /// Maximum score across the entity's tokens
+    Max,
+    /// Mean score across the entity's tokens
+    Average,
+}
+
+impl AggregationStrategy {
+    /// Aggregates the per-token scores of a merged entity into a single confidence score
+    ///
+    /// `scores` must contain at least one element. `Simple` and `First` currently
+    /// produce the same result (see `AggregationStrategy::Simple`).
+    pub fn aggregate(&self, scores: &[f64]) -> f64 {
+        match self {
+            AggregationStrategy::Simple | AggregationStrategy::First => scores[0],
+            AggregationStrategy::Max => scores.iter().cloned().fold(f64::MIN, f64::max),
+            AggregationStrategy::Average => scores.iter().sum::<f64>() / scores.len() as f64,
+        }
+    }
+}
+
+impl Default for AggregationStrategy {
+    fn default() -> Self {
+        AggregationStrategy::Simple
+    }
+}
+
+/// # Configuration for `TokenClassificationModel`
+/// Contains the resources needed to run a token classification pipeline (model
+/// weights, model configuration, tokenizer vocabulary) and the device to place
+/// the model on.
+pub struct TokenClassificationConfig {
+    /// Model type used as the basis for the token classification head
+    pub model_type: ModelType,
+    /// Model weights resource
+    pub model_resource: Resource,
+    /// Model configuration resource
+    pub config_resource: Resource,
+    /// Tokenizer vocabulary resource
+    pub vocab_resource: Resource,
+    /// Lower casing of the input before tokenization
+    pub lower_case: bool,
+    /// Device to place the model on (default: CUDA/GPU when available)
+    pub device: Device,
+    /// Strategy used to compute the confidence score of merged, multi-token entities
+    pub aggregation_strategy: AggregationStrategy,
+}
+
+impl Default for TokenClassificationConfig {
+    /// Defaults to the BERT cased large model finetuned on CoNLL03, contributed by the
+    /// [MDZ Digital Library team at the Bavarian State Library](https://github.com/dbmdz)
+    fn default() -> TokenClassificationConfig {
+        TokenClassificationConfig {
+            model_type: ModelType::Bert,
+            model_resource: Resource::Remote(RemoteResource::from_pretrained(
+                BertModelResources::BERT_NER,
+            )),
+            config_resource: Resource::Remote(RemoteResource::from_pretrained(
+                BertConfigResources::BERT_NER,
+            )),
+            vocab_resource: Resource::Remote(RemoteResource::from_pretrained(
+                BertVocabResources::BERT_NER,
+            )),
+            lower_case: false,
+            device: Device::cuda_if_available(),
+            aggregation_strategy: AggregationStrategy::default(),
+        }
+    }
+}
+
+/// # TokenClassificationModel for generic token-level tagging (NER, POS...)
+pub struct TokenClassificationModel {
+    tokenizer: TokenizerOption,
+    token_classification_model: BertForTokenClassification,
+    label_mapping: Vec<String>,
+    var_store: nn::VarStore,
+}
+
+impl TokenClassificationModel {
+    /// Build a new `TokenClassificationModel`
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - `TokenClassificationConfig` object containing the resource references (model, vocabulary, configuration) and device placement (CPU/GPU)
+    pub fn new(config: TokenClassificationConfig) -> failure::Fallible<TokenClassificationModel> {
+        let device = config.device;
+        let var_store = nn::VarStore::new(device);
+        let tokenizer = TokenizerOption::from_resources(
+            config.model_type,
+            &config.vocab_resource,
+            config.lower_case,
+        )?;
+        let token_classification_model =
+            BertForTokenClassification::new(&var_store.root(), &config.config_resource)?;
+        let label_mapping = token_classification_model.label_mapping().to_vec();
+        Ok(TokenClassificationModel {
+            tokenizer,
+            token_classification_model,
+            label_mapping,
+            var_store,
+        })
+    }
+
+    /// Classify each token of a text sequence
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - `&[&str]` Array of texts to extract tokens and their tags from.
+    /// * `consolidate_sub_tokens` - merge wordpiece/subword continuation pieces back
+    ///   into whole-word tokens, keeping the leading sub-token's label and score.
+    /// * `return_special_tokens` - include special tokens (`[CLS]`, `[SEP]`...) in the
+    ///   returned `Token`s.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Token>` tagged tokens, flattened across all input sentences. Each
+    ///   `Token::sentence` records which input it was extracted from.
+    pub fn predict(
+        &self,
+        input: &[&str],
+        consolidate_sub_tokens: bool,
+        return_special_tokens: bool,
+    ) -> Vec<Token> {
+        let tokenized_input = self.tokenizer.tokenize_list(input.to_vec());
+        let input_tensor = self.tokenizer.convert_tokens_to_ids(&tokenized_input);
+
+        let output = no_grad(|| {
+            self.token_classification_model
+                .forward_t(&input_tensor, &self.var_store)
+        });
+
+        let mut tokens: Vec<Token> = tokenized_input
+            .into_iter()
+            .enumerate()
+            .flat_map(|(sentence_index, sentence_tokens)| {
+                self.decode_sentence(sentence_index, sentence_tokens, &output, return_special_tokens)
+            })
+            .collect();
+
+        if consolidate_sub_tokens {
+            tokens = Self::consolidate_sub_tokens(tokens);
+        }
+        tokens
+    }
+
+    fn decode_sentence(
+        &self,
+        sentence_index: usize,
+        sentence_tokens: Vec<(String, Mask, Option<Offset>)>,
+        output: &Tensor,
+        return_special_tokens: bool,
+    ) -> Vec<Token> {
+        sentence_tokens
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (_, mask, _))| return_special_tokens || *mask != Mask::Special)
+            .map(|(token_index, (text, mask, offset))| {
+                let (label, score) =
+                    self.label_for_position(&output, sentence_index, token_index);
+                Token {
+                    text,
+                    score,
+                    label,
+                    sentence: sentence_index,
+                    offset,
+                    mask,
+                }
+            })
+            .collect()
+    }
+
+    fn label_for_position(&self, output: &Tensor, sentence: usize, position: usize) -> (String, f64) {
+        let logits = output.get(sentence as i64).get(position as i64);
+        let probabilities = logits.softmax(-1, tch::Kind::Double);
+        let (score, label_index) = probabilities.max_dim(0, false);
+        let label_index = i64::from(label_index) as usize;
+        (
+            self.label_mapping[label_index].clone(),
+            f64::from(score),
+        )
+    }
+
+    /// Merges consecutive wordpiece/subword continuation tokens into a single
+    /// whole-word `Token`, keeping the leading sub-token's label and score.
+    fn consolidate_sub_tokens(tokens: Vec<Token>) -> Vec<Token> {
+        let mut consolidated: Vec<Token> = Vec::new();
+        for token in tokens {
+            match (token.mask, consolidated.last_mut()) {
+                (Mask::Continuation, Some(previous)) if previous.sentence == token.sentence => {
+                    previous.text.push_str(token.text.trim_start_matches("##"));
+                    if let (Some(previous_offset), Some(token_offset)) =
+                        (previous.offset.as_mut(), token.offset)
+                    {
+                        previous_offset.end = token_offset.end;
+                    }
+                }
+                _ => consolidated.push(token),
+            }
+        }
+        consolidated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple_and_first_take_the_leading_token_score() {
+        let scores = [0.5, 0.9, 0.7];
+        assert_eq!(AggregationStrategy::Simple.aggregate(&scores), 0.5);
+        assert_eq!(AggregationStrategy::First.aggregate(&scores), 0.5);
+    }
+
+    #[test]
+    fn max_takes_the_highest_token_score() {
+        let scores = [0.5, 0.9, 0.7];
+        assert_eq!(AggregationStrategy::Max.aggregate(&scores), 0.9);
+    }
+
+    #[test]
+    fn average_takes_the_mean_token_score() {
+        let scores = [0.5, 0.9, 0.8];
+        let expected = (0.5 + 0.9 + 0.8) / 3.0;
+        assert!((AggregationStrategy::Average.aggregate(&scores) - expected).abs() < 1e-12);
+    }
+
+    fn token(text: &str, sentence: usize, begin: usize, end: usize, mask: Mask) -> Token {
+        Token {
+            text: text.to_string(),
+            score: 0.9,
+            label: "O".to_string(),
+            sentence,
+            offset: Some(Offset { begin, end }),
+            mask,
+        }
+    }
+
+    #[test]
+    fn continuation_token_merges_text_and_extends_the_offset() {
+        let tokens = vec![
+            token("Bar", 0, 0, 3, Mask::None),
+            token("##ух", 0, 3, 5, Mask::Continuation),
+        ];
+        let consolidated = TokenClassificationModel::consolidate_sub_tokens(tokens);
+        assert_eq!(consolidated.len(), 1);
+        assert_eq!(consolidated[0].text, "Barух");
+        assert_eq!(consolidated[0].offset, Some(Offset { begin: 0, end: 5 }));
+    }
+
+    #[test]
+    fn continuation_token_does_not_merge_across_sentence_boundaries() {
+        let tokens = vec![
+            token("Bar", 0, 0, 3, Mask::None),
+            token("##ух", 1, 0, 2, Mask::Continuation),
+        ];
+        let consolidated = TokenClassificationModel::consolidate_sub_tokens(tokens);
+        assert_eq!(consolidated.len(), 2);
+        assert_eq!(consolidated[0].text, "Bar");
+        assert_eq!(consolidated[1].text, "##ух");
+    }
+}